@@ -32,17 +32,21 @@
 #![warn(missing_docs)]
 
 extern crate hyper;
+extern crate hyper_rustls;
 #[macro_use]
 extern crate error_chain;
 extern crate serde_json;
 extern crate futures;
 
 use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use futures::Future;
 use futures::Stream;
-use hyper::Client;
-use hyper::Uri;
+use futures::future::{self, Either};
+use hyper::{Body, Client, Request, Response as HyperResponse, Uri};
 use hyper::client::HttpConnector;
+use hyper_rustls::HttpsConnector;
 use serde_json::Value;
 
 /// The successful result of an `IpApi::request` call.
@@ -94,6 +98,16 @@ pub struct Coordinates {
     pub longitude: f32,
 }
 
+/// ip-api.com's client-side rate-limit state, read from the `X-Rl`/`X-Ttl` headers of the most
+/// recent response. Returned by [`IpApi::rate_limit`](struct.IpApi.html#method.rate_limit).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// Requests remaining in the current window.
+    pub remaining: u32,
+    /// Time until the window resets and `remaining` goes back up.
+    pub reset_in: Duration,
+}
+
 #[allow(missing_docs)]
 mod error {
     use super::*;
@@ -104,6 +118,23 @@ mod error {
             SerdeJsonError(serde_json::Error);
             FromUtf8Error(std::string::FromUtf8Error);
         }
+
+        errors {
+            /// ip-api.com did not report `"status":"success"` for this query (e.g. it returned
+            /// `"status":"fail"` because the IP is in a reserved/private range or the query string
+            /// could not be parsed), or a batch response was not a JSON array.
+            ApiFailure(message: String) {
+                description("ip-api.com reported a failure")
+                display("ip-api.com reported a failure: {}", message)
+            }
+
+            /// Returned by `IpApi::request`/`IpApi::request_batch` when rate-limit enforcement is
+            /// enabled and the last response reported zero requests remaining.
+            RateLimited(reset_in: Duration) {
+                description("rate limited by ip-api.com")
+                display("rate limited by ip-api.com, resets in {:?}", reset_in)
+            }
+        }
     }
 }
 
@@ -126,28 +157,401 @@ pub use error::*;
 /// # }
 /// ```
 pub struct IpApi {
-    client: Client<HttpConnector>,
+    client: InnerClient,
+    base_url: String,
+    api_key: Option<String>,
+    rate_limit: Arc<Mutex<Option<RateLimit>>>,
+    enforce_rate_limit: bool,
+}
+
+/// The free tier is served over plain HTTP; the Pro tier additionally supports HTTPS via
+/// `rustls`. Both `Client`s expose the same `hyper::client::ResponseFuture`, so they can be
+/// dispatched through without the rest of `IpApi` needing to be generic over the connector.
+enum InnerClient {
+    Http(Client<HttpConnector>),
+    Https(Client<HttpsConnector<HttpConnector>>),
+}
+
+impl InnerClient {
+    fn get(&self, uri: Uri) -> hyper::client::ResponseFuture {
+        match *self {
+            InnerClient::Http(ref client) => client.get(uri),
+            InnerClient::Https(ref client) => client.get(uri),
+        }
+    }
+
+    fn request(&self, request: Request<Body>) -> hyper::client::ResponseFuture {
+        match *self {
+            InnerClient::Http(ref client) => client.request(request),
+            InnerClient::Https(ref client) => client.request(request),
+        }
+    }
+}
+
+/// One field of a [`Response`](struct.Response.html) that can be requested individually through
+/// [`RequestBuilder::fields`](struct.RequestBuilder.html#method.fields).
+///
+/// Restricting the requested fields shrinks the response body ip-api.com sends back, which helps
+/// stay comfortably under the rate limit when only a subset of the data is needed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    /// The IP the query was made for. Always included, regardless of selection.
+    Query,
+    /// `country` and `countryCode`
+    Country,
+    /// `regionName` and `region`
+    Region,
+    /// `city`
+    City,
+    /// `zip`
+    Zip,
+    /// `lat` and `lon`
+    Location,
+    /// `timezone`
+    Timezone,
+    /// `isp`
+    Isp,
+    /// `org`
+    Organization,
+    /// `as`
+    AutonomousSystem,
+    /// `reverse`
+    Reverse,
+    /// `mobile`
+    Mobile,
+    /// `proxy`
+    Proxy,
+}
+
+impl Field {
+    /// Every field this crate knows how to parse. Note that this is a superset of ip-api's own
+    /// default field set: `Reverse` triggers a server-side reverse-DNS lookup that delays the
+    /// response, and `Mobile`/`Proxy` aren't returned unless asked for either, so requesting
+    /// `Field::ALL` is slower than the default `IpApi::request`.
+    pub const ALL: &'static [Field] = &[
+        Field::Query,
+        Field::Country,
+        Field::Region,
+        Field::City,
+        Field::Zip,
+        Field::Location,
+        Field::Timezone,
+        Field::Isp,
+        Field::Organization,
+        Field::AutonomousSystem,
+        Field::Reverse,
+        Field::Mobile,
+        Field::Proxy,
+    ];
+
+    /// The fields ip-api.com returns when no `fields` parameter is given at all. This is what
+    /// `IpApi::request`/`IpApi::request_target` use by default, so their output matches the
+    /// plain, un-customized endpoint.
+    pub const DEFAULT: &'static [Field] = &[
+        Field::Query,
+        Field::Country,
+        Field::Region,
+        Field::City,
+        Field::Zip,
+        Field::Location,
+        Field::Timezone,
+        Field::Isp,
+        Field::Organization,
+        Field::AutonomousSystem,
+    ];
+
+    fn keys(self) -> &'static [&'static str] {
+        match self {
+            Field::Query => &["query"],
+            Field::Country => &["country", "countryCode"],
+            Field::Region => &["regionName", "region"],
+            Field::City => &["city"],
+            Field::Zip => &["zip"],
+            Field::Location => &["lat", "lon"],
+            Field::Timezone => &["timezone"],
+            Field::Isp => &["isp"],
+            Field::Organization => &["org"],
+            Field::AutonomousSystem => &["as"],
+            Field::Reverse => &["reverse"],
+            Field::Mobile => &["mobile"],
+            Field::Proxy => &["proxy"],
+        }
+    }
+}
+
+/// A language ip-api.com can localize `country`, `regionName` and `city` names into, set via
+/// [`RequestBuilder::lang`](struct.RequestBuilder.html#method.lang).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lang {
+    /// English (default)
+    En,
+    /// Deutsch
+    De,
+    /// Español
+    Es,
+    /// Português - Brasil
+    PtBr,
+    /// Français
+    Fr,
+    /// 日本語
+    Ja,
+    /// 中文
+    ZhCn,
+    /// Русский
+    Ru,
+}
+
+impl Lang {
+    fn as_query_value(self) -> &'static str {
+        match self {
+            Lang::En => "en",
+            Lang::De => "de",
+            Lang::Es => "es",
+            Lang::PtBr => "pt-BR",
+            Lang::Fr => "fr",
+            Lang::Ja => "ja",
+            Lang::ZhCn => "zh-CN",
+            Lang::Ru => "ru",
+        }
+    }
+}
+
+/// What `IpApi::request_target`/`RequestBuilder::target` should query: the host machine's own
+/// external IP, a specific IP address, or a hostname for ip-api.com to resolve itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryTarget {
+    /// Query the external IP address of the host machine.
+    SelfIp,
+    /// Query a specific IP address.
+    Ip(IpAddr),
+    /// Query a hostname or domain, e.g. `"www.facebook.com"`.
+    Host(String),
+}
+
+impl From<IpAddr> for QueryTarget {
+    fn from(ip: IpAddr) -> Self {
+        QueryTarget::Ip(ip)
+    }
+}
+
+impl From<Option<IpAddr>> for QueryTarget {
+    fn from(ip: Option<IpAddr>) -> Self {
+        ip.map(QueryTarget::Ip).unwrap_or(QueryTarget::SelfIp)
+    }
+}
+
+impl From<String> for QueryTarget {
+    fn from(host: String) -> Self {
+        QueryTarget::Host(host)
+    }
+}
+
+impl<'a> From<&'a str> for QueryTarget {
+    fn from(host: &'a str) -> Self {
+        QueryTarget::Host(host.to_owned())
+    }
+}
+
+/// Builds a customized call to `IpApi::request`, returned by
+/// [`IpApi::request_builder`](struct.IpApi.html#method.request_builder).
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ip_api;
+/// use ip_api::{IpApi, Field, Lang};
+///
+/// # fn main() {
+/// let ip_api = IpApi::new();
+/// let builder = ip_api.request_builder()
+///     .fields(&[Field::Country, Field::City])
+///     .lang(Lang::De);
+/// # }
+/// ```
+pub struct RequestBuilder<'a> {
+    api: &'a IpApi,
+    target: QueryTarget,
+    fields: &'a [Field],
+    lang: Option<Lang>,
+}
+
+impl<'a> RequestBuilder<'a> {
+    /// Sets the IP address to query. If left unset, the external IP address of the host machine
+    /// is used.
+    pub fn ip(mut self, ip: IpAddr) -> Self {
+        self.target = QueryTarget::Ip(ip);
+        self
+    }
+
+    /// Sets the query target: the host machine's own IP, a specific IP address, or a hostname
+    /// for ip-api.com to resolve. If left unset, the external IP address of the host machine is
+    /// used.
+    pub fn target(mut self, target: impl Into<QueryTarget>) -> Self {
+        self.target = target.into();
+        self
+    }
+
+    /// Restricts the response to the given fields. Unselected fields are left as `None` (or
+    /// `false` for `mobile` and `proxy`) on the resulting `Response`. `Field::Query` is always
+    /// included, even if omitted here.
+    pub fn fields(mut self, fields: &'a [Field]) -> Self {
+        self.fields = fields;
+        self
+    }
+
+    /// Requests `country`, `regionName` and `city` to be localized into the given language.
+    pub fn lang(mut self, lang: Lang) -> Self {
+        self.lang = Some(lang);
+        self
+    }
+
+    /// Sends the request and resolves to the parsed `Response`.
+    pub fn send(self) -> impl Future<Item=Response, Error=Error> + 'a {
+        let uri_string = build_request_uri(
+            &self.api.base_url,
+            &self.target,
+            self.fields,
+            self.lang,
+            self.api.api_key.as_ref().map(String::as_str),
+        );
+
+        self.api.send_request(uri_string)
+    }
+}
+
+fn build_request_uri(
+    base_url: &str,
+    target: &QueryTarget,
+    fields: &[Field],
+    lang: Option<Lang>,
+    api_key: Option<&str>,
+) -> String {
+    let mut keys: Vec<&'static str> = fields.iter()
+        .flat_map(|field| field.keys().iter().cloned())
+        .collect();
+    for required in &["query", "status", "message"] {
+        if !keys.contains(required) {
+            keys.push(required);
+        }
+    }
+
+    let target_path = match *target {
+        QueryTarget::SelfIp => "".to_owned(),
+        QueryTarget::Ip(ip) => "/".to_owned() + &ip.to_string(),
+        QueryTarget::Host(ref host) => "/".to_owned() + &percent_encode_path_segment(host),
+    };
+    let mut uri_string = base_url.to_owned() + "/json" + &target_path
+        + "?fields=" + &keys.join(",");
+    if let Some(lang) = lang {
+        uri_string += "&lang=";
+        uri_string += lang.as_query_value();
+    }
+    if let Some(api_key) = api_key {
+        uri_string += "&key=";
+        uri_string += api_key;
+    }
+
+    uri_string
 }
 
 impl IpApi {
-    /// Constructs a new `IpApi`.
+    /// Constructs a new `IpApi` on the free HTTP endpoint.
     pub fn new() -> Self {
         IpApi {
-            client: Client::new(),
+            client: InnerClient::Http(Client::new()),
+            base_url: "http://ip-api.com".to_owned(),
+            api_key: None,
+            rate_limit: Arc::new(Mutex::new(None)),
+            enforce_rate_limit: false,
+        }
+    }
+
+    /// Constructs an `IpApi` for the Pro tier, authenticated with `api_key`.
+    ///
+    /// Pro requests go out over HTTPS (via `rustls`) to `pro.ip-api.com`, and are subject to the
+    /// higher rate limits of the account the key belongs to.
+    pub fn pro(api_key: String) -> Self {
+        let connector = HttpsConnector::new(4);
+        IpApi {
+            client: InnerClient::Https(Client::builder().build(connector)),
+            base_url: "https://pro.ip-api.com".to_owned(),
+            api_key: Some(api_key),
+            rate_limit: Arc::new(Mutex::new(None)),
+            enforce_rate_limit: false,
+        }
+    }
+
+    /// Opts into client-side rate-limit enforcement: once a response reports zero requests
+    /// remaining, `request`/`request_batch` return `ErrorKind::RateLimited` immediately instead
+    /// of firing the HTTP call and risking an IP ban.
+    pub fn with_rate_limit_enforcement(mut self) -> Self {
+        self.enforce_rate_limit = true;
+        self
+    }
+
+    /// Returns the rate-limit state reported by the most recent response, or `None` if no
+    /// request has been made yet.
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        *self.rate_limit.lock().unwrap()
+    }
+
+    fn rate_limit_error(&self) -> Option<Error> {
+        if !self.enforce_rate_limit {
+            return None;
+        }
+
+        self.rate_limit.lock().unwrap().and_then(|rate_limit| {
+            if rate_limit.remaining == 0 {
+                Some(ErrorKind::RateLimited(rate_limit.reset_in).into())
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Returns a `RequestBuilder` to customize which fields are returned and in which language,
+    /// before sending the request with `RequestBuilder::send`.
+    pub fn request_builder<'a>(&'a self) -> RequestBuilder<'a> {
+        RequestBuilder {
+            api: self,
+            target: QueryTarget::SelfIp,
+            fields: Field::DEFAULT,
+            lang: None,
         }
     }
 
     /// Requests information about the provided IP address.
     /// If no IP address is provided, the external IP address of the host machine is used.
     pub fn request<'a>(&'a self, ip: Option<IpAddr>) -> impl Future<Item=Response, Error=Error> + 'a {
-        let ip_string = ip.map(|ip| "/".to_owned() + &ip.to_string())
-            .unwrap_or("".to_owned());
-        let uri = (&("http://ip-api.com/json".to_owned() + &ip_string)).parse::<Uri>()
+        let mut builder = self.request_builder();
+        builder.target = ip.into();
+        builder.send()
+    }
+
+    /// Requests information about a query target: the host machine's own external IP, a specific
+    /// IP address, or a hostname ip-api.com will resolve itself.
+    ///
+    /// This lets callers geolocate a domain, e.g. `"www.facebook.com"`, without resolving DNS
+    /// themselves first.
+    pub fn request_target<'a>(&'a self, target: impl Into<QueryTarget>) -> impl Future<Item=Response, Error=Error> + 'a {
+        let mut builder = self.request_builder();
+        builder.target = target.into();
+        builder.send()
+    }
+
+    fn send_request<'a>(&'a self, uri_string: String) -> impl Future<Item=Response, Error=Error> + 'a {
+        if let Some(error) = self.rate_limit_error() {
+            return Either::A(future::err(error));
+        }
+
+        let uri = (&uri_string).parse::<Uri>()
             .expect("Could not create the ip-api request URL.
                     \nThis is an implementation error, please report it to the authors.");
+        let rate_limit = self.rate_limit.clone();
 
-        self.client.get(uri)
-            .and_then(|response| {
+        Either::B(self.client.get(uri)
+            .and_then(move |response| {
+                store_rate_limit(&rate_limit, &response);
                 response.into_body()
                     .map(|chunk| chunk.to_vec())
                     .collect()
@@ -162,25 +566,122 @@ impl IpApi {
                 serde_json::from_str::<Value>(&response_string)
                     .map_err(Error::from)
             })
-            .map(move |json| {
-                Response {
-                    query: get_string(&json, "query")
-                        .expect("The queried IP was not in the response."),
-                    country: get_name_and_code(&json, "country", "countryCode"),
-                    region: get_name_and_code(&json, "regionName", "region"),
-                    city: get_string(&json, "city"),
-                    zip: get_string(&json, "zip"),
-                    location: get_coordinates(&json, "lat", "lon"),
-                    timezone: get_string(&json, "timezone"),
-                    isp: get_string(&json, "isp"),
-                    organization: get_string(&json, "org"),
-                    autonomous_system: get_string(&json, "as"),
-                    reverse: get_string(&json, "reverse"),
-                    mobile: get_bool(&json, "mobile"),
-                    proxy: get_bool(&json, "proxy"),
-                }
+            .and_then(|json| response_from_json(&json)))
+    }
+
+    /// Requests information about up to 100 IP addresses in a single HTTP round-trip, using
+    /// ip-api.com's `/batch` endpoint.
+    ///
+    /// This cuts the number of requests needed compared to calling `request` in a loop, which
+    /// helps stay under the 150 requests/minute limit. Each element of the returned `Vec` is its
+    /// own `Result`, since ip-api.com can fail individual queries (e.g. a reserved/private IP)
+    /// within an otherwise successful batch; the overall `Future` only errors when the whole
+    /// call failed, such as a malformed request or a rate-limit/failure envelope in place of the
+    /// expected array.
+    pub fn request_batch<'a>(&'a self, ips: &[IpAddr]) -> impl Future<Item=Vec<Result<Response>>, Error=Error> + 'a {
+        if let Some(error) = self.rate_limit_error() {
+            return Either::A(future::err(error));
+        }
+
+        let queries: Vec<String> = ips.iter().map(|ip| ip.to_string()).collect();
+        let body = serde_json::to_vec(&queries)
+            .expect("Could not serialize the batch request body.
+                    \nThis is an implementation error, please report it to the authors.");
+
+        let mut uri_string = self.base_url.clone() + "/batch";
+        if let Some(ref api_key) = self.api_key {
+            uri_string += "?key=";
+            uri_string += api_key;
+        }
+
+        let request = Request::post(uri_string.as_str())
+            .header("Content-Type", "application/json")
+            .body(Body::from(body))
+            .expect("Could not create the ip-api batch request.
+                    \nThis is an implementation error, please report it to the authors.");
+        let rate_limit = self.rate_limit.clone();
+
+        Either::B(self.client.request(request)
+            .and_then(move |response| {
+                store_rate_limit(&rate_limit, &response);
+                response.into_body()
+                    .map(|chunk| chunk.to_vec())
+                    .collect()
+                    .map(|vec| vec.concat())
+            })
+            .map_err(Error::from)
+            .and_then(|data| {
+                String::from_utf8(data)
+                    .map_err(Error::from)
+            })
+            .and_then(|response_string| {
+                serde_json::from_str::<Value>(&response_string)
+                    .map_err(Error::from)
             })
+            .and_then(|json| {
+                match json.as_array() {
+                    Some(results) => Ok(results.iter().map(response_from_json).collect()),
+                    None => Err(response_from_json(&json).err().unwrap_or_else(|| {
+                        ErrorKind::ApiFailure(
+                            "ip-api.com did not return a JSON array for a batch request".to_owned()
+                        ).into()
+                    })),
+                }
+            }))
+    }
+}
+
+fn percent_encode_path_segment(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char);
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn store_rate_limit(rate_limit: &Arc<Mutex<Option<RateLimit>>>, response: &HyperResponse<Body>) {
+    let remaining = response.headers().get("X-Rl")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u32>().ok());
+    let reset_in = response.headers().get("X-Ttl")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .map(Duration::from_secs);
+
+    if let (Some(remaining), Some(reset_in)) = (remaining, reset_in) {
+        *rate_limit.lock().unwrap() = Some(RateLimit { remaining, reset_in });
+    }
+}
+
+fn response_from_json(json: &Value) -> Result<Response> {
+    if get_string(json, "status").as_ref().map(String::as_str) != Some("success") {
+        let message = get_string(json, "message")
+            .unwrap_or_else(|| "ip-api.com did not report success for this query".to_owned());
+        return Err(ErrorKind::ApiFailure(message).into());
     }
+
+    Ok(Response {
+        query: get_string(json, "query")
+            .expect("ip-api.com reported success but the response had no query field.
+                    \nThis is an implementation error, please report it to the authors."),
+        country: get_name_and_code(json, "country", "countryCode"),
+        region: get_name_and_code(json, "regionName", "region"),
+        city: get_string(json, "city"),
+        zip: get_string(json, "zip"),
+        location: get_coordinates(json, "lat", "lon"),
+        timezone: get_string(json, "timezone"),
+        isp: get_string(json, "isp"),
+        organization: get_string(json, "org"),
+        autonomous_system: get_string(json, "as"),
+        reverse: get_string(json, "reverse"),
+        mobile: get_bool(json, "mobile"),
+        proxy: get_bool(json, "proxy"),
+    })
 }
 
 fn get_coordinates(json: &Value, latitude_index: &str, longitude_index: &str) -> Option<Coordinates> {
@@ -252,4 +753,59 @@ mod tests {
 
         core.run(future).unwrap();
     }
+
+    #[test]
+    fn field_keys_cover_expected_json_keys() {
+        assert_eq!(Field::Query.keys(), &["query"]);
+        assert_eq!(Field::Country.keys(), &["country", "countryCode"]);
+        assert_eq!(Field::Region.keys(), &["regionName", "region"]);
+        assert_eq!(Field::Location.keys(), &["lat", "lon"]);
+        assert_eq!(Field::Reverse.keys(), &["reverse"]);
+    }
+
+    #[test]
+    fn lang_as_query_value_matches_ip_api_codes() {
+        assert_eq!(Lang::En.as_query_value(), "en");
+        assert_eq!(Lang::PtBr.as_query_value(), "pt-BR");
+        assert_eq!(Lang::ZhCn.as_query_value(), "zh-CN");
+    }
+
+    #[test]
+    fn percent_encode_path_segment_escapes_reserved_bytes() {
+        assert_eq!(percent_encode_path_segment("www.facebook.com"), "www.facebook.com");
+        assert_eq!(percent_encode_path_segment("a b/c"), "a%20b%2Fc");
+    }
+
+    #[test]
+    fn build_request_uri_defaults_to_self_and_default_fields() {
+        let uri = build_request_uri("http://ip-api.com", &QueryTarget::SelfIp, Field::DEFAULT, None, None);
+        assert_eq!(
+            uri,
+            "http://ip-api.com/json?fields=query,country,countryCode,regionName,region,city,zip,lat,lon,timezone,isp,org,as,status,message"
+        );
+    }
+
+    #[test]
+    fn build_request_uri_encodes_host_targets_and_appends_lang_and_key() {
+        let target = QueryTarget::Host("www.facebook.com".to_owned());
+        let uri = build_request_uri(
+            "https://pro.ip-api.com",
+            &target,
+            &[Field::Country],
+            Some(Lang::De),
+            Some("secret-key"),
+        );
+        assert_eq!(
+            uri,
+            "https://pro.ip-api.com/json/www.facebook.com?fields=country,countryCode,query,status,message&lang=de&key=secret-key"
+        );
+    }
+
+    #[test]
+    fn query_target_from_conversions() {
+        assert_eq!(QueryTarget::from(None::<IpAddr>), QueryTarget::SelfIp);
+        let ip: IpAddr = "8.8.8.8".parse().unwrap();
+        assert_eq!(QueryTarget::from(Some(ip)), QueryTarget::Ip(ip));
+        assert_eq!(QueryTarget::from("example.com"), QueryTarget::Host("example.com".to_owned()));
+    }
 }